@@ -1,9 +1,14 @@
 use std::env;
+use std::fs;
+use std::process::Command;
 
 use colored::*;
+use git2::DescribeFormatOptions;
+use git2::DescribeOptions;
 use git2::Error;
 use git2::Oid;
 use git2::Repository;
+use git2::RepositoryState;
 
 struct FileState {
     wt_add: usize,
@@ -11,7 +16,8 @@ struct FileState {
     wt_remove: usize,
     index_add: usize,
     index_edit: usize,
-    index_remove: usize
+    index_remove: usize,
+    conflicted: usize
 }
 
 impl FileState {
@@ -24,6 +30,14 @@ impl FileState {
         FileState::as_string(self.index_add, self.index_edit, self.index_remove)
     }
 
+    fn conflicted_as_string(&self) -> String {
+        if self.conflicted > 0 {
+            format!(" ={}", self.conflicted)
+        } else {
+            "".to_owned()
+        }
+    }
+
     fn as_string(add: usize, edit: usize, remove: usize) -> String {
         let mut result = "".to_owned();
         if add > 0 {
@@ -44,33 +58,88 @@ struct BranchState {
     ahead: usize,
     behind: usize,
     is_detached: bool,
-    sha: Oid
+    sha: Oid,
+    describe: Option<String>
 }
 
 impl BranchState {
-    fn as_string(&self) -> String {
+    fn name_as_string(&self) -> String {
         let mut result = format!("{}", self.name);
         if self.is_detached {
-            result.push_str(&format!("({})", self.sha))
+            let detached_label = match &self.describe {
+                Some(describe) => describe.to_owned(),
+                None => self.sha.to_string()
+            };
+            result.push_str(&format!("({})", detached_label))
         }
+        result
+    }
+
+    fn ahead_as_string(&self) -> String {
+        if self.ahead > 0 {
+            format!(" ↑{}", self.ahead)
+        } else {
+            "".to_owned()
+        }
+    }
+
+    fn behind_as_string(&self) -> String {
         if self.behind > 0 {
-            result.push_str(&format!(" ↓{}", self.behind))
+            format!(" ↓{}", self.behind)
+        } else {
+            "".to_owned()
         }
-        if self.ahead > 0 {
-            result.push_str(&format!(" ↑{}", self.ahead))
+    }
+
+    fn diverged_as_string(&self) -> String {
+        if self.ahead > 0 && self.behind > 0 {
+            " ⇕".to_owned()
+        } else {
+            "".to_owned()
+        }
+    }
+}
+
+struct OperationState {
+    label: &'static str,
+    progress: Option<(usize, usize)>
+}
+
+impl OperationState {
+    fn as_string(&self) -> String {
+        let mut result = format!("|{}", self.label);
+        if let Some((cur, total)) = self.progress {
+            result.push_str(&format!(" {}/{}", cur, total));
         }
         result
     }
 }
 
+enum OutputMode {
+    Colored,
+    Vars
+}
+
+struct RepoInfo {
+    branch: BranchState,
+    files: FileState,
+    stash_count: usize,
+    operation: Option<OperationState>
+}
+
 fn main() -> Result<(), Error> {
+    let mode = match env::args().nth(1) {
+        Some(flag) if flag == "--vars" => OutputMode::Vars,
+        _ => OutputMode::Colored
+    };
+
     let current_dir = match env::current_dir() {
         Ok(dir) => dir,
         Err(error) => return add_context_to_error(Error::from_str(&error.to_string()), "Unable to get current dir")
     };
     match Repository::discover(current_dir) {
         Ok(repo) => {
-            get_repo_info(&repo)?
+            get_repo_info(&repo, mode)?
         },
         Err(_) => { } // Not a git dir
     };
@@ -78,31 +147,179 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn get_repo_info(repo: &Repository) -> Result<(), Error> {
-    print_bold_string("[".to_owned(), Color::Cyan);
+const DEFAULT_FORMAT: &str = "$branch$behind$ahead$index$worktree$conflicted$stash$state";
+const FORMAT_CONFIG_KEY: &str = "prompt.format";
+const FORMAT_ENV_KEY: &str = "RGP_FORMAT";
+
+fn get_repo_info(repo: &Repository, mode: OutputMode) -> Result<(), Error> {
+    let mut branch = get_branch_info(&repo)?;
 
-    let branch_state = get_branch_info(&repo)?;
-    print_bold_string(branch_state.as_string(), Color::Cyan);
+    let files = if should_use_git_cli_status(&repo) {
+        let (files, ahead_behind) = get_status_via_git_cli(&repo)?;
+        if let Some((ahead, behind)) = ahead_behind {
+            branch.ahead = ahead;
+            branch.behind = behind;
+        }
+        files
+    } else {
+        get_file_state(&repo)?
+    };
+
+    let info = RepoInfo {
+        branch,
+        files,
+        stash_count: get_stash_info(&repo)?,
+        operation: get_operation_state(&repo)
+    };
 
-    let file_state = get_file_state(&repo)?;
-    let index_text = file_state.index_as_string();
-    let wt_text = file_state.wt_as_string();
+    match mode {
+        OutputMode::Colored => print_formatted(&info, &get_format(repo)),
+        OutputMode::Vars => print_vars(&info)
+    }
 
-    if !index_text.is_empty() {
-        print_bold_string(index_text, Color::Green);
+    Ok(())
+}
 
-        if !wt_text.is_empty() {
-            print_bold_string(" |".to_owned(), Color::Cyan)
+fn get_format(repo: &Repository) -> String {
+    if let Ok(format) = env::var(FORMAT_ENV_KEY) {
+        return format;
+    }
+    if let Ok(config) = repo.config() {
+        if let Ok(format) = config.get_string(FORMAT_CONFIG_KEY) {
+            return format;
         }
     }
+    DEFAULT_FORMAT.to_owned()
+}
 
-    if !wt_text.is_empty() {
-        print_bold_string(wt_text, Color::Red);
-    }
+fn print_formatted(info: &RepoInfo, format: &str) {
+    print_bold_string("[".to_owned(), Color::Cyan);
+
+    let expanded = format
+        .replace("$branch", &colored_bold(info.branch.name_as_string(), Color::Cyan))
+        .replace("$ahead", &colored_bold(info.branch.ahead_as_string(), Color::Cyan))
+        .replace("$behind", &colored_bold(info.branch.behind_as_string(), Color::Cyan))
+        .replace("$diverged", &colored_bold(info.branch.diverged_as_string(), Color::Cyan))
+        .replace("$index", &index_segment(info))
+        .replace("$worktree", &worktree_segment(info))
+        .replace("$conflicted", &colored_bold(info.files.conflicted_as_string(), Color::Magenta))
+        .replace("$stash", &stash_segment(info))
+        .replace("$state", &state_segment(info));
+    print!("{}", expanded);
 
     print_bold_string("]".to_owned(), Color::Cyan);
+}
 
-    Ok(())
+fn index_segment(info: &RepoInfo) -> String {
+    let index_text = info.files.index_as_string();
+    if index_text.is_empty() {
+        return "".to_owned();
+    }
+    colored_bold(index_text, Color::Green)
+}
+
+fn worktree_segment(info: &RepoInfo) -> String {
+    let wt_text = info.files.wt_as_string();
+    if wt_text.is_empty() {
+        return "".to_owned();
+    }
+
+    let mut result = "".to_owned();
+    if !info.files.index_as_string().is_empty() {
+        result.push_str(&colored_bold(" |".to_owned(), Color::Cyan));
+    }
+    result.push_str(&colored_bold(wt_text, Color::Red));
+    result
+}
+
+fn stash_segment(info: &RepoInfo) -> String {
+    if info.stash_count == 0 {
+        return "".to_owned();
+    }
+    colored_bold(format!(" ⚯{}", info.stash_count), Color::Yellow)
+}
+
+fn state_segment(info: &RepoInfo) -> String {
+    match &info.operation {
+        Some(operation_state) => {
+            colored_bold(" ".to_owned(), Color::Cyan) + &colored_bold(operation_state.as_string(), Color::Yellow)
+        },
+        None => "".to_owned()
+    }
+}
+
+fn colored_bold(text: String, colour: Color) -> String {
+    text.color(colour).bold().to_string()
+}
+
+fn print_vars(info: &RepoInfo) {
+    print_var("RGP_BRANCH", &info.branch.name);
+    print_var("RGP_AHEAD", &info.branch.ahead.to_string());
+    print_var("RGP_BEHIND", &info.branch.behind.to_string());
+    print_var("RGP_DETACHED", &info.branch.is_detached.to_string());
+    print_var("RGP_SHA", &info.branch.sha.to_string());
+
+    print_var("RGP_INDEX_ADD", &info.files.index_add.to_string());
+    print_var("RGP_INDEX_EDIT", &info.files.index_edit.to_string());
+    print_var("RGP_INDEX_REMOVE", &info.files.index_remove.to_string());
+    print_var("RGP_WT_ADD", &info.files.wt_add.to_string());
+    print_var("RGP_WT_EDIT", &info.files.wt_edit.to_string());
+    print_var("RGP_WT_REMOVE", &info.files.wt_remove.to_string());
+    print_var("RGP_CONFLICTED", &info.files.conflicted.to_string());
+
+    print_var("RGP_STASH", &info.stash_count.to_string());
+
+    let state = match &info.operation {
+        Some(operation_state) => operation_state.as_string().trim_start_matches('|').to_owned(),
+        None => "".to_owned()
+    };
+    print_var("RGP_STATE", &state);
+}
+
+fn print_var(name: &str, value: &str) {
+    println!("{}={}", name, shell_quote(value));
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn get_stash_info(repo: &Repository) -> Result<usize, Error> {
+    // stash_foreach requires a mutable handle, so open a second handle onto
+    // the same repository rather than threading &mut Repository everywhere.
+    let mut stash_repo = Repository::open(repo.path())?;
+
+    let mut count = 0;
+    stash_repo.stash_foreach(|_, _, _| {
+        count += 1;
+        true
+    })?;
+
+    Ok(count)
+}
+
+fn get_operation_state(repo: &Repository) -> Option<OperationState> {
+    let label = match repo.state() {
+        RepositoryState::Clean => return None,
+        RepositoryState::Merge => "MERGING",
+        RepositoryState::Revert | RepositoryState::RevertSequence => "REVERTING",
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "CHERRY-PICKING",
+        RepositoryState::Bisect => "BISECTING",
+        RepositoryState::Rebase => "REBASING",
+        RepositoryState::RebaseInteractive => "REBASING",
+        RepositoryState::RebaseMerge => "REBASING",
+        RepositoryState::ApplyMailbox => "APPLYING",
+        RepositoryState::ApplyMailboxOrRebase => "APPLYING"
+    };
+
+    Some(OperationState { label, progress: get_rebase_progress(repo) })
+}
+
+fn get_rebase_progress(repo: &Repository) -> Option<(usize, usize)> {
+    let rebase_merge_dir = repo.path().join("rebase-merge");
+    let cur = fs::read_to_string(rebase_merge_dir.join("msgnum")).ok()?;
+    let total = fs::read_to_string(rebase_merge_dir.join("end")).ok()?;
+    Some((cur.trim().parse().ok()?, total.trim().parse().ok()?))
 }
 
 fn get_branch_info(repo: &Repository) -> Result<BranchState, Error> {
@@ -129,12 +346,16 @@ fn get_branch_info(repo: &Repository) -> Result<BranchState, Error> {
         Ok(name) => name,
 
         // No remote branch
-        Err(_) => return Ok(BranchState {
-                                name: head_shortname.to_owned(),
-                                ahead: 0,
-                                behind: 0,
-                                is_detached: repo.head_detached()?,
-                                sha: head.peel_to_commit()?.id() })
+        Err(_) => {
+            let is_detached = repo.head_detached()?;
+            return Ok(BranchState {
+                          name: head_shortname.to_owned(),
+                          ahead: 0,
+                          behind: 0,
+                          is_detached,
+                          sha: head.peel_to_commit()?.id(),
+                          describe: if is_detached { get_describe(repo) } else { None } })
+        }
     };
 
     let remote_reference = match remote_name_buf.as_str() {
@@ -151,13 +372,20 @@ fn get_branch_info(repo: &Repository) -> Result<BranchState, Error> {
     };
 
     let ahead_behind = repo.graph_ahead_behind(local_oid, upstream_oid)?;
+    let is_detached = repo.head_detached()?;
 
     Ok(BranchState {
         name: head_shortname.to_owned(),
         ahead: ahead_behind.0,
         behind: ahead_behind.1,
-        is_detached: repo.head_detached()?,
-        sha: head.peel_to_commit()?.id() })
+        is_detached,
+        sha: head.peel_to_commit()?.id(),
+        describe: if is_detached { get_describe(repo) } else { None } })
+}
+
+fn get_describe(repo: &Repository) -> Option<String> {
+    let describe = repo.describe(DescribeOptions::new().describe_tags().show_commit_oid_as_fallback(true)).ok()?;
+    describe.format(Some(DescribeFormatOptions::new().abbreviated_size(7))).ok()
 }
 
 fn get_empty_repo_branch_info(repo: &Repository) -> Result<BranchState, Error> {
@@ -187,7 +415,8 @@ fn get_empty_repo_branch_info(repo: &Repository) -> Result<BranchState, Error> {
         ahead: 0,
         behind: 0,
         is_detached: false,
-        sha: Oid::zero()
+        sha: Oid::zero(),
+        describe: None
     });
 }
 
@@ -200,7 +429,11 @@ fn get_file_state(repo: &Repository) -> Result<FileState, Error> {
     let mut index_add = 0;
     let mut index_edit = 0;
     let mut index_remove = 0;
+    let mut conflicted = 0;
     for status in statuses.iter().map(|s| s.status()) {
+        if status.is_conflicted() {
+            conflicted += 1;
+        }
         if status.is_wt_new() {
             wt_add += 1;
         }
@@ -229,7 +462,113 @@ fn get_file_state(repo: &Repository) -> Result<FileState, Error> {
         }
     }
 
-    Ok(FileState { wt_add, wt_edit, wt_remove, index_add, index_edit, index_remove })
+    Ok(FileState { wt_add, wt_edit, wt_remove, index_add, index_edit, index_remove, conflicted })
+}
+
+const LARGE_REPO_INDEX_THRESHOLD: usize = 50_000;
+const FAST_STATUS_ENV_KEY: &str = "RGP_FAST_STATUS";
+
+// repo.statuses(None) walks the whole worktree through libgit2, which stalls on
+// very large repositories. Above a size threshold (or when explicitly asked),
+// shell out to `git status` instead: its porcelain v2 format is cheap to parse
+// and git's own status machinery scales better on huge trees.
+fn should_use_git_cli_status(repo: &Repository) -> bool {
+    if let Ok(flag) = env::var(FAST_STATUS_ENV_KEY) {
+        return flag == "1" || flag.eq_ignore_ascii_case("true");
+    }
+
+    match repo.index() {
+        Ok(index) => index.len() > LARGE_REPO_INDEX_THRESHOLD,
+        Err(_) => false
+    }
+}
+
+fn get_status_via_git_cli(repo: &Repository) -> Result<(FileState, Option<(usize, usize)>), Error> {
+    let workdir = match repo.workdir() {
+        Some(dir) => dir,
+        None => return Err(Error::from_str("Unable to run git status on a bare repository"))
+    };
+
+    let output = match Command::new("git").current_dir(workdir).args(&["status", "--porcelain=v2", "--branch", "-z"]).output() {
+        Ok(output) => output,
+        Err(e) => return Err(Error::from_str(&format!("Unable to spawn git: {}", e)))
+    };
+
+    if !output.status.success() {
+        return Err(Error::from_str(&format!("git status failed: {}", String::from_utf8_lossy(&output.stderr))));
+    }
+
+    let stdout = match String::from_utf8(output.stdout) {
+        Ok(stdout) => stdout,
+        Err(e) => return Err(Error::from_str(&format!("git status produced invalid utf8: {}", e)))
+    };
+
+    Ok(parse_porcelain_v2(&stdout))
+}
+
+fn parse_porcelain_v2(stdout: &str) -> (FileState, Option<(usize, usize)>) {
+    let mut wt_add = 0;
+    let mut wt_edit = 0;
+    let mut wt_remove = 0;
+    let mut index_add = 0;
+    let mut index_edit = 0;
+    let mut index_remove = 0;
+    let mut conflicted = 0;
+    let mut ahead_behind = None;
+
+    let mut records = stdout.split('\0').filter(|record| !record.is_empty());
+    while let Some(record) = records.next() {
+        if let Some(header) = record.strip_prefix("# branch.ab ") {
+            ahead_behind = parse_branch_ab(header);
+        } else if record.starts_with('#') || record.starts_with('!') {
+            // Other headers, or ignored files, carry no status we render.
+        } else if record.starts_with("? ") {
+            wt_add += 1;
+        } else if record.starts_with("u ") {
+            conflicted += 1;
+        } else if let Some(xy) = record.strip_prefix("1 ").and_then(|rest| rest.get(0..2)) {
+            apply_xy(xy, &mut index_add, &mut index_edit, &mut index_remove, &mut wt_add, &mut wt_edit, &mut wt_remove);
+        } else if record.starts_with("2 ") {
+            // Renames/copies carry an extra NUL-separated original path record.
+            records.next();
+            index_add += 1;
+            index_remove += 1;
+        }
+    }
+
+    (FileState { wt_add, wt_edit, wt_remove, index_add, index_edit, index_remove, conflicted }, ahead_behind)
+}
+
+fn apply_xy(xy: &str, index_add: &mut usize, index_edit: &mut usize, index_remove: &mut usize, wt_add: &mut usize, wt_edit: &mut usize, wt_remove: &mut usize) {
+    let mut chars = xy.chars();
+    let x = chars.next().unwrap_or('.');
+    let y = chars.next().unwrap_or('.');
+
+    match x {
+        'A' => *index_add += 1,
+        'M' => *index_edit += 1,
+        'D' => *index_remove += 1,
+        _ => { }
+    }
+    match y {
+        'A' => *wt_add += 1,
+        'M' => *wt_edit += 1,
+        'D' => *wt_remove += 1,
+        _ => { }
+    }
+}
+
+fn parse_branch_ab(header: &str) -> Option<(usize, usize)> {
+    let mut ahead = 0;
+    let mut behind = 0;
+    for token in header.split_whitespace() {
+        if let Some(n) = token.strip_prefix('+') {
+            ahead = n.parse().ok()?;
+        } else if let Some(n) = token.strip_prefix('-') {
+            behind = n.parse().ok()?;
+        }
+    }
+    Some((ahead, behind))
 }
 
 fn print_bold_string(text: String, colour: Color) {